@@ -0,0 +1,169 @@
+//! RTIC variant of `adalogger.rs`.
+//!
+//! Instead of `static mut Option<...>` globals accessed `unsafe` from both
+//! `main` and the `USB()` ISR, the USB device, serial port, SD controller
+//! and RTC live as RTIC `#[shared]`/`#[local]` resources. The `USB()`
+//! interrupt is a proper `#[task(binds = USB)]` handler, and the
+//! card-detect pin's `EIC` interrupt only clears its flag and spawns a
+//! software task to do the actual work.
+#![no_std]
+#![no_main]
+
+use feather_m0 as hal;
+use panic_halt as _;
+
+#[rtic::app(device = hal::pac, peripherals = true)]
+mod app {
+    use super::hal;
+    use cortex_m::peripheral::NVIC;
+    use embedded_sdmmc::{Controller, SdMmcSpi, VolumeIdx};
+    use usb_device::bus::UsbBusAllocator;
+    use usb_device::prelude::*;
+    use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+    use hal::clock::{ClockGenId, ClockSource, GenericClockController};
+    use hal::eic::{Eic, ExtInt, Sense};
+    use hal::gpio::v1::{Output, Pa17, Pa27, PullUp, PushPull};
+    use hal::pac::interrupt;
+    use hal::prelude::*;
+    use hal::rtc;
+    use hal::time::U32Ext;
+    use hal::usb::{BufferedSerial, UsbBus};
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBus>,
+        serial: BufferedSerial<'static, UsbBus>,
+    }
+
+    #[local]
+    struct Local {
+        controller: Controller<SdMmcSpi<hal::sercom::SPIMaster4>, rtc::Rtc>,
+        sd_cd: ExtInt<Pa27<PullUp>>,
+        red_led: Pa17<Output<PushPull>>,
+    }
+
+    #[init(local = [usb_allocator: Option<UsbBusAllocator<UsbBus>> = None])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let mut peripherals = cx.device;
+        let core = cx.core;
+
+        let mut clocks = GenericClockController::with_internal_32kosc(
+            peripherals.GCLK,
+            &mut peripherals.PM,
+            &mut peripherals.SYSCTRL,
+            &mut peripherals.NVMCTRL,
+        );
+
+        let timer_clock = clocks
+            .configure_gclk_divider_and_source(ClockGenId::GCLK3, 32, ClockSource::OSC32K, true)
+            .unwrap();
+        let rtc_clock = clocks.rtc(&timer_clock).unwrap();
+        let timer = rtc::Rtc::new(peripherals.RTC, rtc_clock.freq(), &mut peripherals.PM);
+
+        let mut pins = hal::Pins::new(peripherals.PORT);
+        let red_led = pins.d13.into_open_drain_output(&mut pins.port);
+
+        *cx.local.usb_allocator = Some(hal::usb_allocator(
+            peripherals.USB,
+            &mut clocks,
+            &mut peripherals.PM,
+            pins.usb_dm,
+            pins.usb_dp,
+            &mut pins.port,
+        ));
+        let bus_allocator = cx.local.usb_allocator.as_ref().unwrap();
+
+        let serial = BufferedSerial::new(SerialPort::new(bus_allocator));
+        let usb_dev = UsbDeviceBuilder::new(bus_allocator, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Fake company")
+            .product("Serial port")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        let mut core = core;
+        unsafe {
+            core.NVIC.set_priority(interrupt::USB, 1);
+            NVIC::unmask(interrupt::USB);
+        }
+
+        let spi = hal::spi_master(
+            &mut clocks,
+            100_u32.khz(),
+            peripherals.SERCOM4,
+            &mut peripherals.PM,
+            pins.sck,
+            pins.mosi,
+            pins.miso,
+            &mut pins.port,
+        );
+        let mut sd_cs = pins.sd_cs.into_open_drain_output(&mut pins.port);
+        sd_cs.set_high().unwrap();
+
+        let controller = Controller::new(SdMmcSpi::new(spi, sd_cs), timer);
+
+        // Route the card-detect pin through the EIC so inserting/removing
+        // the SD card raises an interrupt instead of requiring polling.
+        let sd_cd = pins.sd_cd.into_pull_up_input(&mut pins.port);
+        let mut eic = Eic::new(&mut clocks, peripherals.EIC, &mut peripherals.PM);
+        let mut sd_cd = eic.new_channel(sd_cd);
+        sd_cd.sense(Sense::Both);
+        sd_cd.enable_interrupt();
+        unsafe {
+            core.NVIC.set_priority(interrupt::EIC, 1);
+            NVIC::unmask(interrupt::EIC);
+        }
+
+        (
+            Shared { usb_dev, serial },
+            Local {
+                controller,
+                sd_cd,
+                red_led,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    #[task(binds = USB, shared = [usb_dev, serial])]
+    fn usb(cx: usb::Context) {
+        let usb_dev = cx.shared.usb_dev;
+        let serial = cx.shared.serial;
+        (usb_dev, serial).lock(|usb_dev, serial| {
+            // `serial` also implements `UsbClass`, forwarding the control
+            // plane to the inner `SerialPort`, so it must be registered
+            // here for the CDC interface to enumerate at all.
+            usb_dev.poll(&mut [serial]);
+            serial.poll();
+        });
+    }
+
+    #[task(binds = EIC, local = [sd_cd])]
+    fn card_detect_irq(cx: card_detect_irq::Context) {
+        cx.local.sd_cd.clear_interrupt_pending_bit();
+        card_detect::spawn().ok();
+    }
+
+    #[task(local = [controller, red_led], shared = [serial])]
+    fn card_detect(cx: card_detect::Context) {
+        let controller = cx.local.controller;
+        let red_led = cx.local.red_led;
+        let mut serial = cx.shared.serial;
+
+        red_led.toggle().ok();
+
+        if controller.device().init().is_ok() {
+            if let Ok(volume) = controller.get_volume(VolumeIdx(0)) {
+                if let Ok(root_dir) = controller.open_root_dir(&volume) {
+                    let _ = controller.iterate_dir(&volume, &root_dir, |entry| {
+                        serial.lock(|serial| {
+                            use core::fmt::Write;
+                            let _ = write!(serial, "Found: {:?}\r\n", entry);
+                        });
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Interrupt-driven buffered USB serial.
+//!
+//! [`BufferedSerial`] wraps a `usbd_serial::SerialPort` with fixed-capacity
+//! TX/RX [`RingBuffer`]s so the `USB()` interrupt can drain/fill the
+//! hardware FIFOs while the application reads and writes without disabling
+//! interrupts on every byte.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use cortex_m::interrupt::free as disable_interrupts;
+use usb_device::bus::UsbBus;
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::UsbError;
+use usbd_serial::SerialPort;
+
+/// Capacity, in bytes, of a [`BufferedSerial`]'s TX and RX [`RingBuffer`]s.
+pub const BUFFER_SIZE: usize = 256;
+
+/// A fixed-capacity, lock-free single-producer/single-consumer byte ring
+/// buffer.
+///
+/// `head` and `tail` are monotonically increasing byte counts (not masked
+/// into `0..N` until indexing `buf`), so occupancy is always `tail - head`
+/// and "full" vs "empty" never need a separately-mutated counter that
+/// producer and consumer could race on. The producer only ever writes
+/// `tail`, the consumer only ever writes `head`, so `push` and `pop_into`
+/// are safe to call concurrently from, e.g., the `USB()` interrupt on one
+/// side and application code on the other, with no critical section.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Safety: `push` only ever writes the `buf` slots between the current
+// `tail` and `tail + to_copy`, which `pop_into` cannot yet observe because
+// `tail` (published with `Release`) isn't advanced until after the write;
+// symmetrically `pop_into` only reads slots below the published `tail` and
+// only writes `head` after it has finished reading them. The two sides
+// never touch the same byte at the same time.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    /// Creates an empty ring buffer.
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `true` if the buffer holds no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if the buffer has no room left for more bytes.
+    pub fn is_full(&self) -> bool {
+        self.occupied() == N
+    }
+
+    fn occupied(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    /// Copies as many bytes from `data` as there is room for, wrapping
+    /// around the end of the backing array as needed.
+    ///
+    /// Returns the number of bytes actually copied. Safe to call
+    /// concurrently with [`RingBuffer::pop_into`] from another context
+    /// (e.g. an interrupt handler).
+    pub fn push(&self, data: &[u8]) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Relaxed);
+        let to_copy = data.len().min(N - tail.wrapping_sub(head));
+
+        // Safety: only the producer writes through this pointer, and only
+        // into slots at or after `tail`, which the consumer won't read
+        // until `tail` is published below.
+        let buf = unsafe { &mut *self.buf.get() };
+        for (i, &byte) in data[..to_copy].iter().enumerate() {
+            buf[tail.wrapping_add(i) % N] = byte;
+        }
+        self.tail.store(tail.wrapping_add(to_copy), Ordering::Release);
+        to_copy
+    }
+
+    /// Drains as many bytes as are available into `data`.
+    ///
+    /// Returns the number of bytes actually copied. Safe to call
+    /// concurrently with [`RingBuffer::push`] from another context (e.g.
+    /// an interrupt handler).
+    pub fn pop_into(&self, data: &mut [u8]) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Relaxed);
+        let to_copy = data.len().min(tail.wrapping_sub(head));
+
+        // Safety: only the consumer writes `head`, and it only reads
+        // slots below the `tail` it just observed, which the producer has
+        // already finished writing.
+        let buf = unsafe { &*self.buf.get() };
+        for (i, slot) in data[..to_copy].iter_mut().enumerate() {
+            *slot = buf[head.wrapping_add(i) % N];
+        }
+        self.head.store(head.wrapping_add(to_copy), Ordering::Release);
+        to_copy
+    }
+}
+
+/// A `usbd_serial::SerialPort` wrapper that buffers reads and writes in
+/// fixed-capacity [`RingBuffer`]s.
+///
+/// Register it as a USB class (it forwards [`UsbClass`] to the inner
+/// `SerialPort`) and call [`BufferedSerial::poll`] from the `USB()`
+/// interrupt, in that order, to service the hardware FIFOs; the
+/// application then uses [`BufferedSerial::write`] and
+/// [`BufferedSerial::read`] without needing to disable interrupts.
+pub struct BufferedSerial<'a, B: UsbBus> {
+    port: SerialPort<'a, B>,
+    tx: RingBuffer<BUFFER_SIZE>,
+    rx: RingBuffer<BUFFER_SIZE>,
+    /// Bytes popped off `tx` that the endpoint hasn't accepted yet.
+    ///
+    /// Only [`BufferedSerial::poll`] ever touches this, so `tx` keeps a
+    /// single consumer: a short or `WouldBlock` write is retried from here
+    /// next time round instead of being pushed back onto `tx`, which would
+    /// make `poll` (running in the `USB()` interrupt) a second producer
+    /// racing with [`BufferedSerial::write`] (running in application
+    /// context).
+    pending: [u8; 64],
+    pending_len: usize,
+}
+
+impl<'a, B: UsbBus> BufferedSerial<'a, B> {
+    /// Wraps `port` with empty TX/RX ring buffers.
+    pub fn new(port: SerialPort<'a, B>) -> Self {
+        Self {
+            port,
+            tx: RingBuffer::new(),
+            rx: RingBuffer::new(),
+            pending: [0u8; 64],
+            pending_len: 0,
+        }
+    }
+
+    /// Services the USB hardware: drains incoming bytes into the RX ring
+    /// and flushes as much of the TX ring as the endpoint will accept.
+    ///
+    /// Call this from the `USB()` interrupt handler, after `UsbDevice::poll`
+    /// has run with this instance registered as one of its classes.
+    pub fn poll(&mut self) {
+        let mut chunk = [0u8; 64];
+        // Only take as many bytes off the port as the RX ring has room
+        // for; the rest stay queued in `SerialPort` until the next poll.
+        let room = (BUFFER_SIZE - self.rx.occupied()).min(chunk.len());
+        if room > 0 {
+            if let Ok(count) = self.port.read(&mut chunk[..room]) {
+                if count > 0 {
+                    self.rx.push(&chunk[..count]);
+                }
+            }
+        }
+
+        if self.pending_len == 0 && !self.tx.is_empty() {
+            self.pending_len = self.tx.pop_into(&mut self.pending);
+        }
+
+        if self.pending_len > 0 {
+            match self.port.write(&self.pending[..self.pending_len]) {
+                Ok(sent) if sent < self.pending_len => {
+                    // The endpoint didn't take it all; shift the
+                    // remainder to the front and retry next poll.
+                    self.pending.copy_within(sent..self.pending_len, 0);
+                    self.pending_len -= sent;
+                }
+                Ok(_) => self.pending_len = 0,
+                Err(UsbError::WouldBlock) => {}
+                Err(_) => self.pending_len = 0,
+            }
+        }
+    }
+
+    /// Enqueues `data` into the TX ring without blocking.
+    ///
+    /// Returns the number of bytes actually enqueued; the caller should
+    /// retry with the remainder if the ring was full.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        self.tx.push(data)
+    }
+
+    /// Dequeues as many bytes as are available from the RX ring.
+    ///
+    /// Returns the number of bytes actually copied.
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        self.rx.pop_into(data)
+    }
+
+    /// Blocks, spinning on [`BufferedSerial::poll`], until the TX ring has
+    /// fully drained out over the wire.
+    ///
+    /// Runs each `poll` with interrupts disabled: `flush` is called from
+    /// application context, and without a critical section a concurrent
+    /// `USB()` interrupt could call `poll` at the same time, driving the
+    /// same `&mut self.port` from two places at once.
+    pub fn flush(&mut self) {
+        while !self.tx.is_empty() || self.pending_len > 0 {
+            disable_interrupts(|_| self.poll());
+        }
+    }
+}
+
+impl<'a, B: UsbBus> core::fmt::Write for BufferedSerial<'a, B> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = self.write(bytes);
+            bytes = &bytes[written..];
+            if written == 0 {
+                // TX ring is full; service the hardware and try again.
+                // Same reasoning as `flush`: this runs in application
+                // context, so disable interrupts around `poll` to avoid
+                // racing a concurrent `USB()` interrupt over `self.port`.
+                disable_interrupts(|_| self.poll());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwards the USB control-plane to the inner `SerialPort`, so a
+/// `BufferedSerial` can be registered directly with `UsbDeviceBuilder`/
+/// `UsbDevice::poll` in place of a bare `SerialPort`.
+impl<'a, B: UsbBus> UsbClass<B> for BufferedSerial<'a, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.port.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.port.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.port.control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.port.control_out(xfer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+
+    #[test]
+    fn empty_buffer_reports_empty() {
+        let rb = RingBuffer::<4>::new();
+        assert!(rb.is_empty());
+        assert!(!rb.is_full());
+    }
+
+    #[test]
+    fn push_then_pop_round_trips() {
+        let rb = RingBuffer::<4>::new();
+        assert_eq!(rb.push(b"ab"), 2);
+        let mut out = [0u8; 2];
+        assert_eq!(rb.pop_into(&mut out), 2);
+        assert_eq!(&out, b"ab");
+        assert!(rb.is_empty());
+    }
+
+    #[test]
+    fn push_stops_at_capacity() {
+        let rb = RingBuffer::<4>::new();
+        assert_eq!(rb.push(b"abcdef"), 4);
+        assert!(rb.is_full());
+        assert_eq!(rb.push(b"xy"), 0);
+    }
+
+    #[test]
+    fn pop_returns_only_whats_available() {
+        let rb = RingBuffer::<4>::new();
+        rb.push(b"ab");
+        let mut out = [0u8; 4];
+        assert_eq!(rb.pop_into(&mut out), 2);
+        assert_eq!(&out[..2], b"ab");
+    }
+
+    #[test]
+    fn wraps_around_the_backing_array() {
+        let rb = RingBuffer::<4>::new();
+        assert_eq!(rb.push(b"abcd"), 4);
+        let mut out = [0u8; 2];
+        assert_eq!(rb.pop_into(&mut out), 2);
+        assert_eq!(&out, b"ab");
+
+        // Pushing again now has to wrap tail back around to index 0.
+        assert_eq!(rb.push(b"ef"), 2);
+        let mut out = [0u8; 4];
+        assert_eq!(rb.pop_into(&mut out), 4);
+        assert_eq!(&out, b"cdef");
+        assert!(rb.is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+//! `defmt` logging transport routed over buffered USB CDC serial.
+//!
+//! Registering [`init`] makes `defmt::info!`/`warn!`/etc. push their
+//! rzcobs-encoded frames into a [`BufferedSerial`]'s TX ring, so logs go
+//! out over the same USB cable as the rest of the application and can be
+//! decoded host-side with `defmt-print`. This avoids the `alloc`/`String`
+//! formatting that a `write!`-based text logger needs on-device.
+
+use cortex_m::register::primask;
+
+use super::buffered::BufferedSerial;
+use super::UsbBus;
+
+static mut SERIAL: Option<&'static mut BufferedSerial<'static, UsbBus>> = None;
+static mut TAKEN: bool = false;
+static mut PRIMASK_WAS_ACTIVE: bool = false;
+
+/// Installs `serial` as the destination for `defmt` log frames.
+///
+/// Must be called once, before the first `defmt` log statement, with a
+/// `'static` reference to the same [`BufferedSerial`] that the `USB()`
+/// interrupt polls.
+pub fn init(serial: &'static mut BufferedSerial<'static, UsbBus>) {
+    unsafe {
+        SERIAL = Some(serial);
+    }
+}
+
+#[defmt::global_logger]
+struct UsbSerialLogger;
+
+unsafe impl defmt::Logger for UsbSerialLogger {
+    fn acquire() {
+        // `feather_m0` targets the SAMD21 (Cortex-M0+ / thumbv6m), which
+        // has no LDREX/STREX and thus no atomic read-modify-write: disable
+        // interrupts *first*, so the load-then-store below can't race
+        // with a nested call from an interrupt, instead of using
+        // `AtomicBool::swap` to guard it.
+        let was_active = primask::read().is_active();
+        unsafe { cortex_m::interrupt::disable() };
+
+        unsafe {
+            if TAKEN {
+                panic!("defmt logger taken re-entrantly");
+            }
+            TAKEN = true;
+            PRIMASK_WAS_ACTIVE = was_active;
+        }
+    }
+
+    unsafe fn flush() {
+        if let Some(serial) = SERIAL.as_mut() {
+            serial.flush();
+        }
+    }
+
+    unsafe fn release() {
+        TAKEN = false;
+        if PRIMASK_WAS_ACTIVE {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        if let Some(serial) = SERIAL.as_mut() {
+            // `BufferedSerial::write` is non-blocking and may enqueue
+            // fewer bytes than given; looping on its return value (as
+            // `core::fmt::Write for BufferedSerial` already does) avoids
+            // silently truncating a frame mid-stream, which would desync
+            // the host-side rzcobs decoder.
+            let mut remaining = bytes;
+            while !remaining.is_empty() {
+                let written = serial.write(remaining);
+                remaining = &remaining[written..];
+                if written == 0 {
+                    serial.poll();
+                }
+            }
+        }
+    }
+}
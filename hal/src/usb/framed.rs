@@ -0,0 +1,197 @@
+//! COBS-framed, `postcard`-serialized command protocol over USB serial.
+//!
+//! [`FramedSerial`] wraps a [`BufferedSerial`] so a host tool can exchange
+//! typed `serde` structs with the board instead of hand-parsing text:
+//! [`FramedSerial::send`] serializes with `postcard`, COBS-encodes the
+//! result, and appends the `0x00` frame delimiter; [`FramedSerial::poll_recv`]
+//! accumulates incoming bytes until a delimiter arrives, COBS-decodes in
+//! place, then deserializes with `postcard`.
+
+use heapless::Vec;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use usb_device::bus::UsbBus;
+
+use super::buffered::BufferedSerial;
+
+/// Maximum size, in bytes, of a single `postcard`-serialized payload
+/// (before COBS encoding).
+pub const MAX_FRAME_SIZE: usize = 256;
+
+/// Upper bound on the size of a COBS-encoded `MAX_FRAME_SIZE`-byte payload:
+/// one extra code byte per 254 input bytes, plus the leading code byte.
+const ENCODED_SIZE: usize = MAX_FRAME_SIZE + MAX_FRAME_SIZE / 254 + 1;
+
+/// COBS-encodes `input` into `output`, returning the number of bytes
+/// written. The trailing `0x00` delimiter is not part of the encoding and
+/// must be appended separately.
+fn cobs_encode(input: &[u8], output: &mut [u8]) -> usize {
+    let mut out = 1;
+    let mut code_idx = 0;
+    let mut code = 1u8;
+
+    for &byte in input {
+        if byte == 0 {
+            output[code_idx] = code;
+            code = 1;
+            code_idx = out;
+            out += 1;
+        } else {
+            output[out] = byte;
+            out += 1;
+            code += 1;
+            if code == 0xFF {
+                output[code_idx] = code;
+                code = 1;
+                code_idx = out;
+                out += 1;
+            }
+        }
+    }
+    output[code_idx] = code;
+    out
+}
+
+/// Decodes a COBS-encoded frame (delimiter already stripped) in place,
+/// returning the number of plaintext bytes written to the front of `buf`.
+fn cobs_decode(buf: &mut [u8]) -> usize {
+    let len = buf.len();
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < len {
+        let code = buf[read] as usize;
+        read += 1;
+        let run_end = (read + code - 1).min(len);
+        for i in read..run_end {
+            buf[write] = buf[i];
+            write += 1;
+        }
+        read = run_end;
+        if code != 0xFF && read < len {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    write
+}
+
+/// A [`BufferedSerial`] wrapper that frames typed structs with COBS and
+/// `postcard` instead of exchanging raw bytes.
+pub struct FramedSerial<'a, B: UsbBus> {
+    serial: BufferedSerial<'a, B>,
+    rx_buf: Vec<u8, ENCODED_SIZE>,
+}
+
+impl<'a, B: UsbBus> FramedSerial<'a, B> {
+    /// Wraps an existing [`BufferedSerial`].
+    pub fn new(serial: BufferedSerial<'a, B>) -> Self {
+        Self {
+            serial,
+            rx_buf: Vec::new(),
+        }
+    }
+
+    /// Services the underlying USB hardware. Call from the `USB()`
+    /// interrupt handler.
+    pub fn poll(&mut self) {
+        self.serial.poll();
+    }
+
+    /// Serializes `value`, COBS-encodes it, and enqueues the framed bytes
+    /// (terminated by `0x00`) for transmission.
+    ///
+    /// Blocks on [`BufferedSerial::flush`] whenever the TX ring is full,
+    /// so the whole frame is always enqueued.
+    pub fn send<T: Serialize>(&mut self, value: &T) -> Result<(), postcard::Error> {
+        let mut plain = [0u8; MAX_FRAME_SIZE];
+        let serialized = postcard::to_slice(value, &mut plain)?;
+
+        let mut framed = [0u8; ENCODED_SIZE];
+        let encoded_len = cobs_encode(serialized, &mut framed);
+        for part in [&framed[..encoded_len], &[0u8][..]] {
+            let mut remaining = part;
+            while !remaining.is_empty() {
+                let written = self.serial.write(remaining);
+                remaining = &remaining[written..];
+                if written == 0 {
+                    self.serial.flush();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Accumulates received bytes and, once a complete `0x00`-delimited
+    /// frame has arrived, COBS-decodes and deserializes it into `T`.
+    ///
+    /// Returns `None` if no complete, valid frame is available yet.
+    pub fn poll_recv<T: DeserializeOwned>(&mut self) -> Option<T> {
+        let mut byte = [0u8; 1];
+        while self.serial.read(&mut byte) == 1 {
+            if byte[0] == 0 {
+                let decoded_len = cobs_decode(&mut self.rx_buf);
+                let value = postcard::from_bytes(&self.rx_buf[..decoded_len]).ok();
+                self.rx_buf.clear();
+                if value.is_some() {
+                    return value;
+                }
+                continue;
+            }
+
+            if self.rx_buf.push(byte[0]).is_err() {
+                // Frame overran the buffer; drop it and resync on the
+                // next delimiter.
+                self.rx_buf.clear();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cobs_decode, cobs_encode, ENCODED_SIZE};
+
+    fn round_trip(input: &[u8]) {
+        let mut encoded = [0u8; ENCODED_SIZE];
+        let encoded_len = cobs_encode(input, &mut encoded);
+        assert!(!encoded[..encoded_len].contains(&0));
+
+        let mut decode_buf = encoded;
+        let decoded_len = cobs_decode(&mut decode_buf[..encoded_len]);
+        assert_eq!(&decode_buf[..decoded_len], input);
+    }
+
+    #[test]
+    fn round_trips_without_zero_bytes() {
+        round_trip(b"hello world");
+    }
+
+    #[test]
+    fn round_trips_with_embedded_zero_bytes() {
+        round_trip(&[1, 0, 2, 0, 0, 3]);
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn round_trips_a_run_longer_than_254_non_zero_bytes() {
+        let input = [0xAAu8; super::MAX_FRAME_SIZE];
+        round_trip(&input);
+    }
+
+    #[test]
+    fn round_trips_a_max_size_payload() {
+        let mut input = [0x42u8; super::MAX_FRAME_SIZE];
+        for (i, byte) in input.iter_mut().enumerate() {
+            if i % 17 == 0 {
+                *byte = 0;
+            }
+        }
+        round_trip(&input);
+    }
+}
@@ -0,0 +1,464 @@
+//! USB Mass Storage Class (SCSI Bulk-Only Transport) support.
+//!
+//! [`MassStorageClass`] exposes any `embedded_sdmmc::BlockDevice` (e.g.
+//! `SdMmcSpi`) as a removable USB drive: READ(10)/WRITE(10) are wired
+//! straight into the block device's `read`/`write`, and READ CAPACITY
+//! reports the block count given to [`MassStorageClass::new`] (the card's
+//! `card_size_bytes()` divided by 512), so an inserted SD card shows up
+//! on the host with no custom software required.
+//!
+//! Bulk endpoints only ever move up to their max packet size (64 bytes
+//! here) per `read`/`write` call, so each 512-byte SD card block is
+//! fragmented across up to 8 packets; [`State::SendingData`] and
+//! [`State::ReceivingData`] track the in-flight block and a byte `offset`
+//! into it across however many `poll` calls that takes.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use usb_device::bus::{InterfaceNumber, UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::endpoint::{EndpointIn, EndpointOut};
+use usb_device::UsbError;
+
+/// USB class code for Mass Storage devices.
+pub const USB_CLASS_MSC: u8 = 0x08;
+/// SCSI transparent command set subclass.
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+/// Bulk-Only Transport protocol.
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+/// SCSI block size used throughout this class, matching the 512-byte
+/// blocks `embedded_sdmmc` reads/writes.
+const BLOCK_SIZE: usize = 512;
+/// Max packet size of the bulk endpoints; a single `read`/`write` call
+/// never moves more than this many bytes.
+const PACKET_SIZE: usize = 64;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+
+/// A parsed SCSI command, with the opcode-specific fields we act on
+/// already pulled out of the command descriptor block.
+#[derive(Debug, PartialEq, Eq)]
+enum ScsiCommand {
+    TestUnitReadyOrRequestSense,
+    Inquiry,
+    ReadCapacity10,
+    Read10 { lba: u32, blocks: u32 },
+    Write10 { lba: u32, blocks: u32 },
+    /// Either genuinely unsupported, a command descriptor block too
+    /// short to safely read the fields its opcode implies, or a
+    /// READ(10)/WRITE(10) whose CBW direction flag doesn't match it.
+    Unsupported,
+}
+
+/// Parses a Command Block Wrapper's tag, transfer direction and SCSI
+/// command.
+///
+/// Returns `None` if `cbw`'s signature doesn't match, i.e. this isn't a
+/// CBW at all. A CDB that's too short for its opcode (including a
+/// zero-length one) is never indexed out of bounds; it just maps to
+/// [`ScsiCommand::Unsupported`] instead of panicking, since `cb_len` is
+/// host-controlled and can't be trusted.
+fn parse_cbw(cbw: &[u8; CBW_LEN]) -> Option<(u32, bool, ScsiCommand)> {
+    if u32::from_le_bytes([cbw[0], cbw[1], cbw[2], cbw[3]]) != CBW_SIGNATURE {
+        return None;
+    }
+
+    let tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+    let data_in = cbw[12] & 0x80 != 0;
+    let cb_len = (cbw[14] as usize).min(16);
+    let cb = &cbw[15..15 + cb_len];
+
+    let command = match cb.first() {
+        Some(&SCSI_TEST_UNIT_READY) | Some(&SCSI_REQUEST_SENSE) => {
+            ScsiCommand::TestUnitReadyOrRequestSense
+        }
+        Some(&SCSI_INQUIRY) => ScsiCommand::Inquiry,
+        Some(&SCSI_READ_CAPACITY_10) => ScsiCommand::ReadCapacity10,
+        Some(&SCSI_READ_10) if data_in && cb.len() >= 10 => {
+            let (lba, blocks) = lba_and_count(cb);
+            ScsiCommand::Read10 { lba, blocks }
+        }
+        Some(&SCSI_WRITE_10) if !data_in && cb.len() >= 10 => {
+            let (lba, blocks) = lba_and_count(cb);
+            ScsiCommand::Write10 { lba, blocks }
+        }
+        _ => ScsiCommand::Unsupported,
+    };
+
+    Some((tag, data_in, command))
+}
+
+/// Reads the LBA and block count out of a READ(10)/WRITE(10) CDB.
+///
+/// `cb` must be at least 10 bytes, as guaranteed by [`parse_cbw`].
+fn lba_and_count(cb: &[u8]) -> (u32, u32) {
+    let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+    let blocks = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+    (lba, blocks)
+}
+
+/// What the class is waiting to do next in the Bulk-Only Transport
+/// command/data/status cycle.
+enum State {
+    /// Waiting for a Command Block Wrapper on the OUT endpoint.
+    AwaitingCommand,
+    /// Streaming `block` out on the IN endpoint, `offset` bytes in, with
+    /// `remaining` total blocks (including this one) left to send for the
+    /// in-progress READ(10).
+    SendingData {
+        lba: u32,
+        remaining: u32,
+        block: Block,
+        offset: usize,
+    },
+    /// Filling `block` from the OUT endpoint, `offset` bytes in, with
+    /// `remaining` total blocks (including this one) left to receive for
+    /// the in-progress WRITE(10).
+    ReceivingData {
+        lba: u32,
+        remaining: u32,
+        block: Block,
+        offset: usize,
+    },
+}
+
+/// A USB Mass Storage Class (SCSI Bulk-Only Transport) device backed by
+/// `D`'s block-level read/write.
+pub struct MassStorageClass<'a, B: UsbBus, D: BlockDevice> {
+    interface: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    device: D,
+    block_count: u32,
+    cbw_tag: u32,
+    state: State,
+}
+
+impl<'a, B: UsbBus, D: BlockDevice> MassStorageClass<'a, B, D> {
+    /// Wraps `device`, allocating bulk endpoints on `bus_alloc`.
+    ///
+    /// `block_count` reflects `embedded_sdmmc`'s `card_size_bytes()`
+    /// divided by the 512-byte SCSI block size used throughout this class.
+    pub fn new(bus_alloc: &'a UsbBusAllocator<B>, device: D, block_count: u32) -> Self {
+        Self {
+            interface: bus_alloc.interface(),
+            read_ep: bus_alloc.bulk(PACKET_SIZE as u16),
+            write_ep: bus_alloc.bulk(PACKET_SIZE as u16),
+            device,
+            block_count,
+            cbw_tag: 0,
+            state: State::AwaitingCommand,
+        }
+    }
+
+    /// Services a pending bulk transfer. Call after each `UsbDevice::poll`.
+    pub fn poll(&mut self) {
+        match core::mem::replace(&mut self.state, State::AwaitingCommand) {
+            State::AwaitingCommand => self.poll_command(),
+            State::SendingData {
+                lba,
+                remaining,
+                block,
+                offset,
+            } => self.poll_send_data(lba, remaining, block, offset),
+            State::ReceivingData {
+                lba,
+                remaining,
+                block,
+                offset,
+            } => self.poll_receive_data(lba, remaining, block, offset),
+        }
+    }
+
+    fn poll_command(&mut self) {
+        let mut cbw = [0u8; CBW_LEN];
+        let count = match self.read_ep.read(&mut cbw) {
+            Ok(count) => count,
+            Err(_) => return,
+        };
+        if count != CBW_LEN {
+            return;
+        }
+
+        let (tag, _data_in, command) = match parse_cbw(&cbw) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        self.cbw_tag = tag;
+
+        match command {
+            ScsiCommand::TestUnitReadyOrRequestSense => self.send_status(0),
+            ScsiCommand::Inquiry => {
+                let status = self.handle_inquiry();
+                self.send_status(status);
+            }
+            ScsiCommand::ReadCapacity10 => {
+                let status = self.handle_read_capacity();
+                self.send_status(status);
+            }
+            ScsiCommand::Read10 { lba, blocks } => self.begin_read(lba, blocks),
+            ScsiCommand::Write10 { lba, blocks } => self.begin_write(lba, blocks),
+            ScsiCommand::Unsupported => self.send_status(1),
+        }
+    }
+
+    fn handle_inquiry(&mut self) -> u8 {
+        let mut reply = [0u8; 36];
+        reply[0] = 0x00; // direct-access block device
+        reply[2] = 0x02; // SPC-2
+        reply[4] = 31; // additional length
+        reply[8..16].copy_from_slice(b"ATSAMD  ");
+        reply[16..32].copy_from_slice(b"SD Card Reader  ");
+        reply[32..36].copy_from_slice(b"1.0 ");
+        match self.write_ep.write(&reply) {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    }
+
+    fn handle_read_capacity(&mut self) -> u8 {
+        let mut reply = [0u8; 8];
+        reply[0..4].copy_from_slice(&(self.block_count.saturating_sub(1)).to_be_bytes());
+        reply[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+        match self.write_ep.write(&reply) {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    }
+
+    /// Begins a READ(10): loads the first block from the device and moves
+    /// to [`State::SendingData`], or completes immediately if `remaining`
+    /// is zero.
+    fn begin_read(&mut self, lba: u32, remaining: u32) {
+        if remaining == 0 {
+            self.send_status(0);
+            return;
+        }
+
+        let mut block = Block::new();
+        let read = self
+            .device
+            .read(core::slice::from_mut(&mut block), BlockIdx(lba), "mass_storage");
+        if read.is_ok() {
+            self.state = State::SendingData {
+                lba,
+                remaining,
+                block,
+                offset: 0,
+            };
+        } else {
+            self.send_status(1);
+        }
+    }
+
+    /// Begins a WRITE(10): moves to [`State::ReceivingData`] to accumulate
+    /// the first block, or completes immediately if `remaining` is zero.
+    fn begin_write(&mut self, lba: u32, remaining: u32) {
+        if remaining == 0 {
+            self.send_status(0);
+            return;
+        }
+
+        self.state = State::ReceivingData {
+            lba,
+            remaining,
+            block: Block::new(),
+            offset: 0,
+        };
+    }
+
+    /// Sends up to one packet's worth of `block`, starting at `offset`.
+    fn poll_send_data(&mut self, lba: u32, remaining: u32, block: Block, offset: usize) {
+        let end = (offset + PACKET_SIZE).min(BLOCK_SIZE);
+        match self.write_ep.write(&block.contents[offset..end]) {
+            Ok(sent) => {
+                let offset = offset + sent;
+                if offset < BLOCK_SIZE {
+                    self.state = State::SendingData {
+                        lba,
+                        remaining,
+                        block,
+                        offset,
+                    };
+                } else if remaining > 1 {
+                    self.begin_read(lba + 1, remaining - 1);
+                } else {
+                    self.send_status(0);
+                }
+            }
+            Err(UsbError::WouldBlock) => {
+                self.state = State::SendingData {
+                    lba,
+                    remaining,
+                    block,
+                    offset,
+                };
+            }
+            Err(_) => self.send_status(1),
+        }
+    }
+
+    /// Receives up to one packet's worth into `block`, starting at
+    /// `offset`, writing the block back to the device once it's full.
+    fn poll_receive_data(&mut self, lba: u32, remaining: u32, mut block: Block, offset: usize) {
+        let want = (BLOCK_SIZE - offset).min(PACKET_SIZE);
+        let mut chunk = [0u8; PACKET_SIZE];
+        match self.read_ep.read(&mut chunk[..want]) {
+            Ok(count) => {
+                block.contents[offset..offset + count].copy_from_slice(&chunk[..count]);
+                let offset = offset + count;
+                if offset < BLOCK_SIZE {
+                    self.state = State::ReceivingData {
+                        lba,
+                        remaining,
+                        block,
+                        offset,
+                    };
+                    return;
+                }
+
+                if self
+                    .device
+                    .write(core::slice::from_ref(&block), BlockIdx(lba))
+                    .is_err()
+                {
+                    self.send_status(1);
+                } else if remaining > 1 {
+                    self.state = State::ReceivingData {
+                        lba: lba + 1,
+                        remaining: remaining - 1,
+                        block: Block::new(),
+                        offset: 0,
+                    };
+                } else {
+                    self.send_status(0);
+                }
+            }
+            Err(UsbError::WouldBlock) => {
+                self.state = State::ReceivingData {
+                    lba,
+                    remaining,
+                    block,
+                    offset,
+                };
+            }
+            Err(_) => self.send_status(1),
+        }
+    }
+
+    fn send_status(&mut self, status: u8) {
+        let mut csw = [0u8; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.cbw_tag.to_le_bytes());
+        csw[12] = status;
+        let _ = self.write_ep.write(&csw);
+        self.state = State::AwaitingCommand;
+    }
+}
+
+impl<'a, B: UsbBus, D: BlockDevice> UsbClass<B> for MassStorageClass<'a, B, D> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.interface, USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT)?;
+        writer.endpoint(&self.read_ep)?;
+        writer.endpoint(&self.write_ep)?;
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        MassStorageClass::poll(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cbw, ScsiCommand, CBW_LEN, CBW_SIGNATURE};
+
+    /// Builds a CBW with the given tag, direction, and CDB, copying in up
+    /// to 16 CDB bytes and declaring `cb_len` in `cbw[14]` independently
+    /// of how many bytes were copied.
+    fn cbw(tag: u32, data_in: bool, cb_len: u8, cdb: &[u8]) -> [u8; CBW_LEN] {
+        let mut buf = [0u8; CBW_LEN];
+        buf[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        buf[4..8].copy_from_slice(&tag.to_le_bytes());
+        buf[12] = if data_in { 0x80 } else { 0x00 };
+        buf[14] = cb_len;
+        let copy_len = cdb.len().min(16);
+        buf[15..15 + copy_len].copy_from_slice(&cdb[..copy_len]);
+        buf
+    }
+
+    #[test]
+    fn rejects_a_non_cbw_buffer() {
+        let buf = [0u8; CBW_LEN];
+        assert_eq!(parse_cbw(&buf), None);
+    }
+
+    #[test]
+    fn zero_length_cdb_is_unsupported_not_a_panic() {
+        let buf = cbw(1, true, 0, &[]);
+        let (tag, _, command) = parse_cbw(&buf).unwrap();
+        assert_eq!(tag, 1);
+        assert_eq!(command, ScsiCommand::Unsupported);
+    }
+
+    #[test]
+    fn read_10_cdb_too_short_is_unsupported_not_a_panic() {
+        // READ(10)'s opcode is present but the CDB is truncated before the
+        // LBA/length fields it relies on.
+        let buf = cbw(2, true, 9, &[super::SCSI_READ_10, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let (_, _, command) = parse_cbw(&buf).unwrap();
+        assert_eq!(command, ScsiCommand::Unsupported);
+    }
+
+    #[test]
+    fn write_10_cdb_too_short_is_unsupported_not_a_panic() {
+        let buf = cbw(3, false, 9, &[super::SCSI_WRITE_10, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let (_, _, command) = parse_cbw(&buf).unwrap();
+        assert_eq!(command, ScsiCommand::Unsupported);
+    }
+
+    #[test]
+    fn parses_a_full_length_read_10_cdb() {
+        let mut cdb = [0u8; 10];
+        cdb[0] = super::SCSI_READ_10;
+        cdb[2..6].copy_from_slice(&42u32.to_be_bytes());
+        cdb[7..9].copy_from_slice(&3u16.to_be_bytes());
+        let buf = cbw(4, true, 10, &cdb);
+
+        let (tag, data_in, command) = parse_cbw(&buf).unwrap();
+        assert_eq!(tag, 4);
+        assert!(data_in);
+        assert_eq!(
+            command,
+            ScsiCommand::Read10 {
+                lba: 42,
+                blocks: 3
+            }
+        );
+    }
+
+    #[test]
+    fn parses_a_full_length_write_10_cdb() {
+        let mut cdb = [0u8; 10];
+        cdb[0] = super::SCSI_WRITE_10;
+        cdb[2..6].copy_from_slice(&7u32.to_be_bytes());
+        cdb[7..9].copy_from_slice(&1u16.to_be_bytes());
+        let buf = cbw(5, false, 10, &cdb);
+
+        let (tag, data_in, command) = parse_cbw(&buf).unwrap();
+        assert_eq!(tag, 5);
+        assert!(!data_in);
+        assert_eq!(command, ScsiCommand::Write10 { lba: 7, blocks: 1 });
+    }
+}
@@ -0,0 +1,20 @@
+//! Higher-level helpers built on top of the SAM `UsbBus`.
+//!
+//! `buffered` adds interrupt-friendly ring buffers around a
+//! `usbd_serial::SerialPort` so the application doesn't have to disable
+//! interrupts on every byte it writes. `framed` builds on top of that to
+//! exchange typed structs instead of raw bytes. `mass_storage` exposes a
+//! block device as a USB Mass Storage drive. `defmt_logger` routes
+//! `defmt`'s log frames out over a buffered serial port.
+
+mod buffered;
+#[cfg(feature = "defmt")]
+mod defmt_logger;
+mod framed;
+mod mass_storage;
+
+pub use buffered::{BufferedSerial, RingBuffer, BUFFER_SIZE};
+#[cfg(feature = "defmt")]
+pub use defmt_logger::init as init_defmt_logger;
+pub use framed::{FramedSerial, MAX_FRAME_SIZE};
+pub use mass_storage::{MassStorageClass, USB_CLASS_MSC};